@@ -1,13 +1,22 @@
+use std::collections::HashMap;
 use std::ffi::CStr;
+use std::fs;
+use std::hash::{Hash, Hasher};
+use std::path::{Path, PathBuf};
 
 use anyhow::anyhow;
+use cap_std::{ambient_authority, fs::Dir};
 use cfx_wasm_rt_types::{call_result::CRITICAL_ERROR, ScrObject};
 
 use wasmtime::*;
 use wasmtime_wasi::{sync::WasiCtxBuilder, Wasi};
+use wasi_common::pipe::WritePipe;
 
 mod invoker;
 
+/// 64 KiB, the fixed size of a single page of WASM linear memory.
+const WASM_PAGE_SIZE: usize = 1 << 16;
+
 pub type LogFunc = extern "C" fn(msg: *const i8);
 pub type CanonicalizeRefFunc =
     extern "C" fn(ref_idx: u32, buffer: *mut i8, buffer_size: u32) -> i32;
@@ -32,58 +41,198 @@ const HOST_INVOKE: &str = "invoke";
 const HOST_CANONICALIZE_REF: &str = "canonicalize_ref";
 const HOST_INVOKE_REF_FUNC: &str = "invoke_ref_func";
 
+// default amount of fuel handed to a script before each guest entry point,
+// tunable at runtime via `Runtime::set_fuel_budget`
+const DEFAULT_FUEL_BUDGET: u64 = 10_000_000;
+
+// default ceiling on a script's linear memory, tunable at runtime via
+// `Runtime::set_max_memory_pages` (256 pages == 16 MiB)
+const DEFAULT_MAX_MEMORY_PAGES: u32 = 256;
+
+/// Sandboxing knobs for `Runtime::load_module`'s WASI path: which host
+/// directories (if any) a script can see, under what guest-visible paths,
+/// and what environment variables it's handed. Scripts get no filesystem
+/// access and no environment unless explicitly granted here.
+#[derive(Default, Clone)]
+pub struct WasiConfig {
+    preopened_dirs: Vec<(PathBuf, String)>,
+    env: Vec<(String, String)>,
+}
+
+impl WasiConfig {
+    pub fn new() -> WasiConfig {
+        WasiConfig::default()
+    }
+
+    /// Preopens `host_path` into the sandbox, visible to the guest at
+    /// `guest_path` (e.g. mapping a resource's own folder to `/resource`).
+    pub fn preopen_dir(mut self, host_path: impl Into<PathBuf>, guest_path: impl Into<String>) -> WasiConfig {
+        self.preopened_dirs.push((host_path.into(), guest_path.into()));
+        self
+    }
+
+    pub fn env(mut self, key: impl Into<String>, value: impl Into<String>) -> WasiConfig {
+        self.env.push((key.into(), value.into()));
+        self
+    }
+}
+
+/// Identifies one of the scripts concurrently loaded into a `Runtime`, as
+/// returned by `load_module`. Opaque to callers; threaded back in to every
+/// other `Runtime` method that targets a specific script.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct ScriptId(u64);
+
 pub struct Runtime {
     engine: Engine,
-    script: Option<ScriptModule>,
+    scripts: HashMap<ScriptId, ScriptModule>,
+    next_script_id: u64,
+    fuel_budget: u64,
+    cache_dir: Option<PathBuf>,
+    // fingerprint of the `Config` used to build `engine`; cached modules are
+    // only reused when this still matches, so a wasmtime upgrade or opt
+    // level change can't load a stale native image
+    config_fingerprint: String,
+    wasi_config: WasiConfig,
+    max_memory_pages: u32,
 }
 
 impl Runtime {
     pub fn new() -> Runtime {
         let mut config = Config::default();
         config.cranelift_opt_level(wasmtime::OptLevel::Speed);
+        config.consume_fuel(true);
 
         let engine = Engine::new(&config).unwrap();
+        let config_fingerprint = format!("{:?}-{}", wasmtime::OptLevel::Speed, wasmtime::VERSION);
 
         Runtime {
             engine,
-            script: None,
+            scripts: HashMap::new(),
+            next_script_id: 0,
+            fuel_budget: DEFAULT_FUEL_BUDGET,
+            cache_dir: None,
+            config_fingerprint,
+            wasi_config: WasiConfig::default(),
+            max_memory_pages: DEFAULT_MAX_MEMORY_PAGES,
         }
     }
 
-    pub fn load_module(&mut self, bytes: &[u8], wasi: bool) -> anyhow::Result<()> {
+    /// Like `new()`, but compiled modules are cached on disk under
+    /// `cache_dir` so that reloading the same bytes on a later server start
+    /// skips Cranelift codegen entirely.
+    pub fn with_cache_dir(cache_dir: impl Into<PathBuf>) -> Runtime {
+        let mut runtime = Runtime::new();
+        runtime.cache_dir = Some(cache_dir.into());
+
+        runtime
+    }
+
+    /// Sets the amount of fuel a script is given before each guest entry
+    /// point (`_start`, events, ticks, ref calls). Once exhausted, the guest
+    /// call traps with `TrapCode::OutOfFuel` and the script is unloaded.
+    pub fn set_fuel_budget(&mut self, budget: u64) {
+        self.fuel_budget = budget;
+    }
+
+    /// Controls the preopened directories and environment a WASI script is
+    /// sandboxed with; takes effect on the next `load_module(.., true)`.
+    pub fn set_wasi_config(&mut self, config: WasiConfig) {
+        self.wasi_config = config;
+    }
+
+    /// Caps a script's linear memory at `pages` (64 KiB each); attempts to
+    /// grow past that fail (`memory.grow` returns -1) instead of exhausting
+    /// host memory.
+    pub fn set_max_memory_pages(&mut self, pages: u32) {
+        self.max_memory_pages = pages;
+    }
+
+    /// Mirrors `memory_size()`: fuel consumed by `id` since its last entry
+    /// point call, for profiling expensive resources.
+    pub fn fuel_consumed(&self, id: ScriptId) -> Option<u64> {
+        self.scripts.get(&id).and_then(ScriptModule::fuel_consumed)
+    }
+
+    /// Compiles and instantiates `bytes` as a new script and runs its
+    /// `_start`, returning an id to address it by in every other method.
+    /// Loading a new script never evicts an already-loaded one.
+    pub fn load_module(&mut self, bytes: &[u8], wasi: bool) -> anyhow::Result<ScriptId> {
+        if is_component_binary(bytes) {
+            let script = ScriptModule::new_component(&self.engine, bytes)?;
+
+            return self.finish_load(script);
+        }
+
+        let module = compile_cached(
+            &self.engine,
+            self.cache_dir.as_deref(),
+            &self.config_fingerprint,
+            bytes,
+        )?;
+
         let script = if wasi {
-            ScriptModule::new_with_wasi(&self.engine, bytes)?
+            ScriptModule::new_with_wasi(
+                &self.engine,
+                &module,
+                &self.wasi_config,
+                self.max_memory_pages,
+                self.fuel_budget,
+            )?
         } else {
-            ScriptModule::new(&self.engine, bytes)?
+            ScriptModule::new(
+                &self.engine,
+                &module,
+                self.max_memory_pages,
+                self.fuel_budget,
+            )?
         };
 
-        self.script = Some(script);
+        self.finish_load(script)
+    }
+
+    /// Registers `script` under a fresh id and runs its `_start`, unloading
+    /// just that script (not the rest of `self.scripts`) if it traps.
+    fn finish_load(&mut self, script: ScriptModule) -> anyhow::Result<ScriptId> {
+        let id = ScriptId(self.next_script_id);
+        self.next_script_id += 1;
 
-        if let Some(start) = self
-            .script
-            .as_ref()
-            .and_then(|script| script.instance.get_func(CFX_START))
-        {
-            start.call(&[])?;
+        self.scripts.insert(id, script);
+
+        if let Some(script) = self.scripts.get(&id) {
+            if let Some(start) = script.instance.get_func(CFX_START) {
+                script.refill_fuel(self.fuel_budget)?;
+
+                if let Err(err) = start.call(&[]) {
+                    self.scripts.remove(&id);
+
+                    return Err(classify_trap(CFX_START, err));
+                }
+            }
         }
 
-        Ok(())
+        Ok(id)
     }
 
-    pub fn unload_module(&mut self) {
-        self.script = None;
+    pub fn unload_module(&mut self, id: ScriptId) {
+        self.scripts.remove(&id);
     }
 
     #[inline]
     pub fn trigger_event(
         &mut self,
+        id: ScriptId,
         event_name: &CStr,
         args: &[u8],
         source: &CStr,
     ) -> anyhow::Result<()> {
-        if let Some(script) = self.script.as_mut() {
+        let fuel_budget = self.fuel_budget;
+
+        if let Some(script) = self.scripts.get_mut(&id) {
             let mut wrapper = || -> anyhow::Result<()> {
                 if let Some(func) = script.on_event.clone() {
+                    script.refill_fuel(fuel_budget)?;
+
                     let ev = script.copy_event_name(event_name)?;
                     let args = script.copy_event_args(args)?;
                     let src = script.copy_event_source(source)?;
@@ -101,46 +250,61 @@ impl Runtime {
             };
 
             if let Err(err) = wrapper() {
-                self.script = None;
-                script_log(format!("{} error: {:?}", CFX_ON_EVENT, err));
+                self.scripts.remove(&id);
 
-                return Err(err);
+                return Err(classify_trap(CFX_ON_EVENT, err));
             }
         }
 
         Ok(())
     }
 
-    pub fn tick(&mut self) -> anyhow::Result<()> {
-        if let Some(func) = self
-            .script
-            .as_ref()
-            .and_then(|script| script.instance.get_func(CFX_ON_TICK))
-        {
-            if let Err(err) = func.call(&[]) {
-                self.script = None;
-                script_log(format!("{} error: {:?}", CFX_ON_TICK, err));
+    /// Fans `event_name` out to every currently loaded script. A script that
+    /// traps handling the event is unloaded same as `trigger_event` would,
+    /// without disturbing the others.
+    pub fn broadcast_event(&mut self, event_name: &CStr, args: &[u8], source: &CStr) {
+        for id in self.script_ids() {
+            let _ = self.trigger_event(id, event_name, args, source);
+        }
+    }
+
+    pub fn tick(&mut self, id: ScriptId) -> anyhow::Result<()> {
+        if let Some(script) = self.scripts.get(&id) {
+            if let Some(func) = script.instance.get_func(CFX_ON_TICK) {
+                script.refill_fuel(self.fuel_budget)?;
+
+                if let Err(err) = func.call(&[]) {
+                    self.scripts.remove(&id);
 
-                return Err(err);
+                    return Err(classify_trap(CFX_ON_TICK, err));
+                }
             }
         }
 
         Ok(())
     }
 
+    /// Ticks every currently loaded script, isolating faults so that one
+    /// script trapping and being unloaded doesn't interrupt the others.
+    pub fn tick_all(&mut self) {
+        for id in self.script_ids() {
+            let _ = self.tick(id);
+        }
+    }
+
     pub fn call_ref(
         &mut self,
+        id: ScriptId,
         ref_idx: u32,
         args: &[u8],
         ret_buf: &mut Vec<u8>,
     ) -> anyhow::Result<u32> {
-        if let Some(script) = self.script.as_ref() {
-            match call_call_ref(script, ref_idx, args, ret_buf) {
+        if let Some(script) = self.scripts.get(&id) {
+            match call_call_ref(script, self.fuel_budget, ref_idx, args, ret_buf) {
                 Err(err) => {
-                    self.script = None;
-                    script_log(format!("{} error: {:?}", CFX_CALL_REF, err));
+                    self.scripts.remove(&id);
 
-                    return Err(err);
+                    return Err(classify_trap(CFX_CALL_REF, err));
                 }
 
                 Ok(val) => return Ok(val),
@@ -150,47 +314,124 @@ impl Runtime {
         Ok(0)
     }
 
-    pub fn duplicate_ref(&mut self, ref_idx: u32) -> u32 {
-        if let Some(func) = self.script.as_ref().and_then(|script| {
-            script
+    pub fn duplicate_ref(&mut self, id: ScriptId, ref_idx: u32) -> u32 {
+        if let Some(script) = self.scripts.get(&id) {
+            if let Ok(func) = script
                 .instance
                 .get_typed_func::<i32, i32>(CFX_DUPLICATE_REF)
-                .ok()
-        }) {
-            match func.call(ref_idx as _).map(|idx| idx as _) {
-                Err(err) => {
-                    self.script = None;
+            {
+                if let Err(err) = script.refill_fuel(self.fuel_budget) {
+                    self.scripts.remove(&id);
                     script_log(format!("{} error: {:?}", CFX_DUPLICATE_REF, err));
+
+                    return 0;
                 }
 
-                Ok(val) => return val,
+                match func.call(ref_idx as _).map(|idx| idx as _) {
+                    Err(err) => {
+                        self.scripts.remove(&id);
+
+                        classify_trap(CFX_DUPLICATE_REF, err);
+                    }
+
+                    Ok(val) => return val,
+                }
             }
         }
 
         0
     }
 
-    pub fn remove_ref(&mut self, ref_idx: u32) {
-        if let Some(func) = self.script.as_ref().and_then(|script| {
-            script
-                .instance
-                .get_typed_func::<i32, i32>(CFX_REMOVE_REF)
-                .ok()
-        }) {
-            if let Err(err) = func.call(ref_idx as _) {
-                self.script = None;
-                script_log(format!("{} error: {:?}", CFX_REMOVE_REF, err));
+    pub fn remove_ref(&mut self, id: ScriptId, ref_idx: u32) {
+        if let Some(script) = self.scripts.get(&id) {
+            if let Ok(func) = script.instance.get_typed_func::<i32, i32>(CFX_REMOVE_REF) {
+                if let Err(err) = script.refill_fuel(self.fuel_budget) {
+                    self.scripts.remove(&id);
+                    script_log(format!("{} error: {:?}", CFX_REMOVE_REF, err));
+
+                    return;
+                }
+
+                if let Err(err) = func.call(ref_idx as _) {
+                    self.scripts.remove(&id);
+
+                    classify_trap(CFX_REMOVE_REF, err);
+                }
             }
         }
     }
 
-    pub fn memory_size(&self) -> u32 {
-        self.script
-            .as_ref()
+    pub fn memory_size(&self, id: ScriptId) -> u32 {
+        self.scripts
+            .get(&id)
             .and_then(|script| script.instance.get_memory("memory"))
             .map(|memory| memory.size())
             .unwrap_or(0)
     }
+
+    fn script_ids(&self) -> Vec<ScriptId> {
+        self.scripts.keys().copied().collect()
+    }
+}
+
+/// Compiles `bytes` into a `Module`, reusing a previously serialized native
+/// image from `cache_dir` when one exists for this exact input and
+/// `config_fingerprint`. Shared by the WASI and non-WASI load paths so
+/// neither skips the cache.
+fn compile_cached(
+    engine: &Engine,
+    cache_dir: Option<&Path>,
+    config_fingerprint: &str,
+    bytes: &[u8],
+) -> anyhow::Result<Module> {
+    let cache_dir = match cache_dir {
+        Some(cache_dir) => cache_dir,
+        None => return Module::new(engine, bytes),
+    };
+
+    let cache_path = cache_dir.join(format!("{:016x}.cwasm", cache_key(config_fingerprint, bytes)));
+
+    if let Ok(cached) = fs::read(&cache_path) {
+        // SAFETY: the cache key is derived from the input bytes and the
+        // config fingerprint, so a hit is an artifact that `compile_cached`
+        // wrote (in this or an earlier run) for this (bytes, config) pair.
+        if let Ok(module) = unsafe { Module::deserialize(engine, &cached) } {
+            return Ok(module);
+        }
+    }
+
+    let module = Module::new(engine, bytes)?;
+
+    if let Ok(serialized) = module.serialize() {
+        if fs::create_dir_all(cache_dir).is_ok() {
+            let _ = fs::write(&cache_path, serialized);
+        }
+    }
+
+    Ok(module)
+}
+
+/// Tells a WASM *component* apart from a plain core module by its binary
+/// preamble. Both start with the `\0asm` magic and a 4-byte version field,
+/// but that field's high 16 bits are a "layer" that is `0` for core modules
+/// and `1` for components (see the component-model binary format).
+fn is_component_binary(bytes: &[u8]) -> bool {
+    if bytes.len() < 8 || bytes[0..4] != *b"\0asm" {
+        return false;
+    }
+
+    let layer = u16::from_le_bytes([bytes[6], bytes[7]]);
+
+    layer == 1
+}
+
+fn cache_key(config_fingerprint: &str, bytes: &[u8]) -> u64 {
+    let mut hasher = std::collections::hash_map::DefaultHasher::new();
+
+    bytes.hash(&mut hasher);
+    config_fingerprint.hash(&mut hasher);
+
+    hasher.finish()
 }
 
 struct ScriptModule {
@@ -199,6 +440,61 @@ struct ScriptModule {
     on_event: Option<Func>,
     event_allocs: EventAlloc,
     memory: Memory,
+    // budget passed to the most recent `refill_fuel` call, so
+    // `fuel_consumed` can report usage since that call rather than the
+    // store's lifetime-cumulative total. A `Cell` so `refill_fuel` can stay
+    // `&self`, matching the rest of the store-mutating methods below.
+    fuel_budget: std::cell::Cell<u64>,
+}
+
+/// Caps a script's linear memory growth at `max_memory_pages`; attached to
+/// the `Store` so a runaway script's growth requests fail instead of
+/// growing without bound.
+struct MemoryLimiter {
+    max_bytes: usize,
+}
+
+impl MemoryLimiter {
+    fn new(max_pages: u32) -> MemoryLimiter {
+        MemoryLimiter {
+            max_bytes: max_pages as usize * WASM_PAGE_SIZE,
+        }
+    }
+}
+
+impl ResourceLimiter for MemoryLimiter {
+    fn memory_growing(&self, _current: usize, desired: usize, _maximum: Option<usize>) -> bool {
+        desired <= self.max_bytes
+    }
+
+    fn table_growing(&self, _current: u32, desired: u32, maximum: Option<u32>) -> bool {
+        maximum.map_or(true, |maximum| desired <= maximum)
+    }
+}
+
+/// Redirects a WASI script's captured stdout/stderr into `LOGGER` line by
+/// line instead of the host console, so guest output shows up alongside the
+/// rest of the runtime's logging.
+#[derive(Default)]
+struct ScriptLogWriter {
+    buf: Vec<u8>,
+}
+
+impl std::io::Write for ScriptLogWriter {
+    fn write(&mut self, data: &[u8]) -> std::io::Result<usize> {
+        self.buf.extend_from_slice(data);
+
+        while let Some(pos) = self.buf.iter().position(|&b| b == b'\n') {
+            let line: Vec<u8> = self.buf.drain(..=pos).collect();
+            script_log(String::from_utf8_lossy(&line[..line.len() - 1]));
+        }
+
+        Ok(data.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
 }
 
 #[derive(Default)]
@@ -209,39 +505,65 @@ struct EventAlloc {
 }
 
 impl ScriptModule {
-    fn new(engine: &Engine, bytes: &[u8]) -> anyhow::Result<ScriptModule> {
-        let store = Store::new(&engine);
-        let module = Module::new(engine, bytes)?;
+    fn new(
+        engine: &Engine,
+        module: &Module,
+        max_memory_pages: u32,
+        fuel_budget: u64,
+    ) -> anyhow::Result<ScriptModule> {
+        let store = Store::new_with_limits(
+            &engine,
+            Box::new(MemoryLimiter::new(max_memory_pages)) as Box<dyn ResourceLimiter>,
+        );
 
-        let instance = Instance::new(&store, &module, &[])?;
+        let instance = Instance::new(&store, module, &[])?;
         let on_event = instance.get_func(CFX_ON_EVENT);
         let memory = instance.get_memory("memory").ok_or(anyhow!("No memory"))?;
 
-        let mut module = ScriptModule {
+        let mut script = ScriptModule {
             store,
             instance,
             on_event,
             memory,
             event_allocs: EventAlloc::default(),
+            fuel_budget: std::cell::Cell::new(0),
         };
 
-        module.make_startup_allocs()?;
+        // __cfx_alloc below needs fuel to run, and the store starts at 0
+        // fuel since `consume_fuel(true)` is set on the engine's config
+        script.refill_fuel(fuel_budget)?;
+        script.make_startup_allocs()?;
 
-        Ok(module)
+        Ok(script)
     }
 
-    fn new_with_wasi(engine: &Engine, bytes: &[u8]) -> anyhow::Result<ScriptModule> {
-        let store = Store::new(&engine);
+    fn new_with_wasi(
+        engine: &Engine,
+        module: &Module,
+        wasi_config: &WasiConfig,
+        max_memory_pages: u32,
+        fuel_budget: u64,
+    ) -> anyhow::Result<ScriptModule> {
+        let store = Store::new_with_limits(
+            &engine,
+            Box::new(MemoryLimiter::new(max_memory_pages)) as Box<dyn ResourceLimiter>,
+        );
         let mut linker = Linker::new(&store);
 
-        let wasi = Wasi::new(
-            &store,
-            WasiCtxBuilder::new()
-                .inherit_stdout()
-                .inherit_stdio()
-                .inherit_stderr()
-                .build(),
-        );
+        let mut ctx_builder = WasiCtxBuilder::new()
+            .stdout(Box::new(WritePipe::new(ScriptLogWriter::default())))
+            .stderr(Box::new(WritePipe::new(ScriptLogWriter::default())));
+
+        for (host_path, guest_path) in &wasi_config.preopened_dirs {
+            let dir = Dir::open_ambient_dir(host_path, ambient_authority())?;
+            ctx_builder = ctx_builder.preopened_dir(dir, guest_path)?;
+        }
+
+        for (key, value) in &wasi_config.env {
+            ctx_builder = ctx_builder.env(key, value)?;
+        }
+
+        let wasi = Wasi::new(&store, ctx_builder.build());
 
         wasi.add_to_linker(&mut linker)?;
 
@@ -298,22 +620,65 @@ impl ScriptModule {
             },
         )?;
 
-        let module = Module::new(engine, bytes)?;
-        let instance = linker.instantiate(&module)?;
+        let instance = linker.instantiate(module)?;
         let on_event = instance.get_func(CFX_ON_EVENT);
         let memory = instance.get_memory("memory").ok_or(anyhow!("No memory"))?;
 
-        let mut module = ScriptModule {
+        let mut script = ScriptModule {
             store,
             instance,
             on_event,
             memory,
             event_allocs: EventAlloc::default(),
+            fuel_budget: std::cell::Cell::new(0),
         };
 
-        module.make_startup_allocs()?;
+        script.refill_fuel(fuel_budget)?;
+        script.make_startup_allocs()?;
+
+        Ok(script)
+    }
+
+    /// Always returns an error: the vendored wasmtime predates
+    /// `wasmtime::component`, so components are not instantiated and the
+    /// `cfx` world in `wit/cfx.wit` is not bound to anything.
+    /// `Runtime::load_module` calls this for component binaries so they get
+    /// a clear rejection instead of failing deep inside `Module::new`.
+    /// Component guests therefore still cannot replace the
+    /// `__cfx_alloc`/`__cfx_free` ABI and `EventAlloc` buffers.
+    fn new_component(_engine: &Engine, _bytes: &[u8]) -> anyhow::Result<ScriptModule> {
+        Err(anyhow!(
+            "component-model scripts are not supported by the vendored wasmtime \
+             version yet; see wit/cfx.wit for the target world"
+        ))
+    }
+
+    /// Tops the store's fuel back up to `budget` ahead of a guest entry
+    /// point call, draining whatever was left over from a previous call so
+    /// scripts can't bank unused fuel across invocations.
+    #[inline]
+    fn refill_fuel(&self, budget: u64) -> anyhow::Result<()> {
+        // `consume_fuel(0)` consumes nothing but returns the current
+        // balance, so this drains exactly the leftover rather than trying
+        // (and failing) to over-consume it
+        if let Ok(remaining) = self.store.consume_fuel(0) {
+            let _ = self.store.consume_fuel(remaining);
+        }
+
+        self.store.add_fuel(budget)?;
+        self.fuel_budget.set(budget);
+
+        Ok(())
+    }
+
+    /// Fuel consumed since the most recent `refill_fuel` call, i.e. during
+    /// the in-flight or most recently completed guest entry point — unlike
+    /// `Store::fuel_consumed`, which accumulates over the store's lifetime.
+    #[inline]
+    fn fuel_consumed(&self) -> Option<u64> {
+        let remaining = self.store.consume_fuel(0).ok()?;
 
-        Ok(module)
+        Some(self.fuel_budget.get().saturating_sub(remaining))
     }
 
     #[inline]
@@ -431,6 +796,7 @@ pub fn set_canonicalize_ref(canonicalize_ref: CanonicalizeRefFunc) {
 
 fn call_call_ref(
     script: &ScriptModule,
+    fuel_budget: u64,
     ref_idx: u32,
     args: &[u8],
     ret_buf: &mut Vec<u8>,
@@ -444,6 +810,7 @@ fn call_call_ref(
         .instance
         .get_typed_func::<(i32, i32, i32), i32>(CFX_CALL_REF)?;
 
+    script.refill_fuel(fuel_budget)?;
     let args_guest = script.alloc_bytes(args)?;
 
     let scrobj = {
@@ -522,6 +889,27 @@ pub(crate) fn script_log<T: AsRef<str>>(msg: T) {
     }
 }
 
+/// Logs `err` against `entry_point`, calling out a fuel exhaustion trap
+/// specifically so operators can tell "script is too slow" apart from a
+/// generic crash. Returns `err` unchanged so callers can keep using `?`.
+fn classify_trap(entry_point: &str, err: anyhow::Error) -> anyhow::Error {
+    let out_of_fuel = err
+        .downcast_ref::<Trap>()
+        .and_then(Trap::trap_code)
+        == Some(TrapCode::OutOfFuel);
+
+    if out_of_fuel {
+        script_log(format!(
+            "{} error: script exceeded execution budget",
+            entry_point
+        ));
+    } else {
+        script_log(format!("{} error: {:?}", entry_point, err));
+    }
+
+    err
+}
+
 pub fn fx_succeeded(result: u32) -> bool {
     (result & 0x80000000) == 0
 }